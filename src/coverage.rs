@@ -0,0 +1,160 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::sync::Mutex;
+
+/// CLI-level coverage-track settings, threaded down into both reading modes.
+pub struct CoverageConfig {
+    pub out_path: Option<String>,
+    pub bin_size: u64,
+}
+
+/// Per-bin read-depth accumulator for reads overlapping a target interval.
+/// Keyed by `(tid, bin_index)` where `bin_index = position / bin_size`, so
+/// concurrent workers in `process_records` can all feed it through a shared
+/// lock rather than each keeping a private table to merge later.
+pub struct CoverageAccumulator {
+    bin_size: u64,
+    depths: Mutex<HashMap<(i32, u64), u64>>,
+}
+
+impl CoverageAccumulator {
+    pub fn new(bin_size: u64) -> Self {
+        CoverageAccumulator {
+            bin_size,
+            depths: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Record that a read covering `[start, end)` on `tid` overlapped a
+    /// target region, bumping the depth of every bin it touches.
+    pub fn add(&self, tid: i32, start: u64, end: u64) {
+        if end <= start {
+            return;
+        }
+        let first_bin = start / self.bin_size;
+        let last_bin = (end - 1) / self.bin_size;
+
+        let mut depths = self.depths.lock().unwrap();
+        for bin in first_bin..=last_bin {
+            *depths.entry((tid, bin)).or_insert(0) += 1;
+        }
+    }
+
+    /// Write a coordinate-sorted bedGraph: `chrom\tstart\tend\tdepth`.
+    pub fn write_bedgraph(&self, path: &str, tid_to_name: &HashMap<i32, String>) -> io::Result<()> {
+        let depths = self.depths.lock().unwrap();
+        let mut entries: Vec<(i32, u64, u64)> = depths.iter()
+            .map(|(&(tid, bin), &depth)| (tid, bin, depth))
+            .collect();
+        entries.sort_by_key(|&(tid, bin, _)| (tid, bin));
+
+        let mut out = BufWriter::new(File::create(path)?);
+        for (tid, bin, depth) in entries {
+            let chrom = tid_to_name.get(&tid).map(String::as_str).unwrap_or("*");
+            let start = bin * self.bin_size;
+            let end = start + self.bin_size;
+            writeln!(out, "{}\t{}\t{}\t{}", chrom, start, end, depth)?;
+        }
+        Ok(())
+    }
+
+    /// Write the same bins as a bigWig, with bigtools' default zoom/summary
+    /// levels. Gated behind the `bigwig` feature since `bigtools` pulls in a
+    /// chunk of extra native code that most builds of this tool don't need.
+    #[cfg(feature = "bigwig")]
+    pub fn write_bigwig(
+        &self,
+        path: &str,
+        tid_to_name: &HashMap<i32, String>,
+        chrom_sizes: &HashMap<String, u32>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        use bigtools::{BigWigWrite, Value};
+        use bigtools::beddata::BedParserStreamingIterator;
+
+        let depths = self.depths.lock().unwrap();
+        let mut entries: Vec<(i32, u64, u64)> = depths.iter()
+            .map(|(&(tid, bin), &depth)| (tid, bin, depth))
+            .collect();
+        entries.sort_by_key(|&(tid, bin, _)| (tid, bin));
+
+        let mut by_chrom: HashMap<String, Vec<Value>> = HashMap::new();
+        for (tid, bin, depth) in entries {
+            let Some(chrom) = tid_to_name.get(&tid) else { continue };
+            by_chrom.entry(chrom.clone()).or_default().push(Value {
+                start: (bin * self.bin_size) as u32,
+                end: ((bin + 1) * self.bin_size) as u32,
+                value: depth as f32,
+            });
+        }
+
+        let writer = BigWigWrite::create_file(path.to_string(), chrom_sizes.clone())?;
+        let vals = BedParserStreamingIterator::from_map(by_chrom);
+        writer.write(vals, bigtools::utils::reopen::ReopenableFile::from_path(path)?)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    fn temp_path() -> std::path::PathBuf {
+        static COUNTER: AtomicU64 = AtomicU64::new(0);
+        let n = COUNTER.fetch_add(1, Ordering::Relaxed);
+        std::env::temp_dir().join(format!("rust_requal-coverage-test-{}-{}.bedgraph", std::process::id(), n))
+    }
+
+    #[test]
+    fn add_bumps_every_bin_a_read_spans() {
+        let acc = CoverageAccumulator::new(10);
+        acc.add(0, 5, 25); // bins 0, 1, 2
+
+        let depths = acc.depths.lock().unwrap();
+        assert_eq!(depths.get(&(0, 0)), Some(&1));
+        assert_eq!(depths.get(&(0, 1)), Some(&1));
+        assert_eq!(depths.get(&(0, 2)), Some(&1));
+        assert_eq!(depths.len(), 3);
+    }
+
+    #[test]
+    fn add_ignores_empty_or_inverted_ranges() {
+        let acc = CoverageAccumulator::new(10);
+        acc.add(0, 5, 5);
+        acc.add(0, 10, 5);
+
+        assert!(acc.depths.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn add_accumulates_depth_across_overlapping_reads() {
+        let acc = CoverageAccumulator::new(10);
+        acc.add(0, 0, 10);
+        acc.add(0, 0, 10);
+
+        assert_eq!(acc.depths.lock().unwrap().get(&(0, 0)), Some(&2));
+    }
+
+    #[test]
+    fn write_bedgraph_emits_coordinate_sorted_rows() {
+        let acc = CoverageAccumulator::new(10);
+        acc.add(1, 0, 10);
+        acc.add(0, 20, 30);
+        acc.add(0, 0, 10);
+
+        let mut tid_to_name = HashMap::new();
+        tid_to_name.insert(0, "chr1".to_string());
+        tid_to_name.insert(1, "chr2".to_string());
+
+        let path = temp_path();
+        acc.write_bedgraph(path.to_str().unwrap(), &tid_to_name).unwrap();
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            contents,
+            "chr1\t0\t10\t1\nchr1\t20\t30\t1\nchr2\t0\t10\t1\n"
+        );
+    }
+}
@@ -0,0 +1,117 @@
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use rust_htslib::bam::Record;
+use std::fs::File;
+use std::io::{self, Write};
+use std::sync::Mutex;
+
+/// CLI-level FASTQ-extraction settings, threaded down into both reading modes.
+pub struct FastqConfig {
+    pub out_prefix: Option<String>,
+}
+
+/// Writes target-overlapping reads out as gzipped FASTQ, splitting mate 1/2
+/// by the paired flag into `PREFIX_1.fq.gz`/`PREFIX_2.fq.gz` so the pair can
+/// be fed straight back into an aligner. Guarded by a `Mutex` per file like
+/// `CoverageAccumulator`, since `process_records` calls in from many threads.
+pub struct FastqWriter {
+    r1: Mutex<GzEncoder<File>>,
+    r2: Mutex<GzEncoder<File>>,
+}
+
+impl FastqWriter {
+    pub fn create(prefix: &str) -> io::Result<Self> {
+        let r1 = File::create(format!("{}_1.fq.gz", prefix))?;
+        let r2 = File::create(format!("{}_2.fq.gz", prefix))?;
+        Ok(FastqWriter {
+            r1: Mutex::new(GzEncoder::new(r1, Compression::default())),
+            r2: Mutex::new(GzEncoder::new(r2, Compression::default())),
+        })
+    }
+
+    /// Append `record` as a FASTQ entry, reverse-complementing the sequence
+    /// and reversing the quality string if it's mapped to the reverse strand
+    /// so the output is in original read orientation. Second-in-pair reads
+    /// go to the `_2` file; everything else (unpaired or first-in-pair) goes
+    /// to `_1`.
+    pub fn write(&self, record: &Record) -> io::Result<()> {
+        let name = String::from_utf8_lossy(record.qname());
+        let (seq, qual) = if record.is_reverse() {
+            (revcomp(record.seq().as_bytes()), reverse(record.qual()))
+        } else {
+            (record.seq().as_bytes(), record.qual().to_vec())
+        };
+        let seq: String = seq.iter().map(|&b| b as char).collect();
+        let qual = qual_to_fastq(&qual);
+
+        let target = if record.is_paired() && record.is_last_in_template() {
+            &self.r2
+        } else {
+            &self.r1
+        };
+        let mut out = target.lock().unwrap();
+        writeln!(out, "@{}\n{}\n+\n{}", name, seq, qual)
+    }
+
+    /// Flush and close both gzip streams, writing their footers.
+    pub fn finish(self) -> io::Result<()> {
+        self.r1.into_inner().unwrap().finish()?;
+        self.r2.into_inner().unwrap().finish()?;
+        Ok(())
+    }
+}
+
+fn revcomp(seq: Vec<u8>) -> Vec<u8> {
+    seq.iter()
+        .rev()
+        .map(|&b| match b {
+            b'A' => b'T',
+            b'T' => b'A',
+            b'C' => b'G',
+            b'G' => b'C',
+            other => other,
+        })
+        .collect()
+}
+
+fn reverse(qual: &[u8]) -> Vec<u8> {
+    qual.iter().rev().copied().collect()
+}
+
+/// Render a Phred-scaled quality array as a FASTQ quality line.
+///
+/// `record.qual()` returns a "fake" quality of `0xFF` per base when the
+/// original record has no quality string at all; `0xff + 33` would overflow
+/// `u8`, so that case is rendered as the single `*` SAM itself uses for
+/// "no quality" rather than added into unconditionally.
+fn qual_to_fastq(qual: &[u8]) -> String {
+    if qual.iter().all(|&q| q == 0xff) {
+        return "*".to_string();
+    }
+    qual.iter().map(|&q| (q + 33) as char).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn revcomp_reverses_and_complements_bases() {
+        assert_eq!(revcomp(b"ACGTN".to_vec()), b"NACGT".to_vec());
+    }
+
+    #[test]
+    fn reverse_reverses_byte_order() {
+        assert_eq!(reverse(&[1, 2, 3]), vec![3, 2, 1]);
+    }
+
+    #[test]
+    fn qual_to_fastq_encodes_phred_scores() {
+        assert_eq!(qual_to_fastq(&[0, 1, 40]), "!\"I");
+    }
+
+    #[test]
+    fn qual_to_fastq_handles_missing_quality_without_overflow() {
+        assert_eq!(qual_to_fastq(&[0xff, 0xff, 0xff]), "*");
+    }
+}
@@ -1,275 +1,822 @@
-use rust_htslib::bam::{self, Read, Record};
-use std::fs::File;
-use std::io::{self, BufRead};
-use intervaltree::IntervalTree;
-use std::collections::HashMap;
-use rayon::prelude::*;
-use crossbeam::channel::{bounded, Receiver, Sender};
-use clap::{Arg, Command};
-use std::error::Error;
-use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::Arc;
-use std::cmp::Ordering as CmpOrdering;
-
-#[derive(Debug)]
-enum ProcessError {
-    IoError(io::Error),
-    BamError(rust_htslib::errors::Error),
-    SendError(String),
-}
-
-impl From<io::Error> for ProcessError {
-    fn from(err: io::Error) -> ProcessError {
-        ProcessError::IoError(err)
-    }
-}
-
-impl From<rust_htslib::errors::Error> for ProcessError {
-    fn from(err: rust_htslib::errors::Error) -> ProcessError {
-        ProcessError::BamError(err)
-    }
-}
-
-impl std::error::Error for ProcessError {}
-
-impl std::fmt::Display for ProcessError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            ProcessError::IoError(e) => write!(f, "IO error: {}", e),
-            ProcessError::BamError(e) => write!(f, "BAM error: {}", e),
-            ProcessError::SendError(e) => write!(f, "Send error: {}", e),
-        }
-    }
-}
-
-#[derive(Clone)]
-struct OrderedRecord {
-    index: usize,
-    record: Record,
-}
-
-fn read_bed_file(bed_file: &str) -> Result<HashMap<String, IntervalTree<u64, ()>>, Box<dyn Error>> {
-    let mut interval_data: HashMap<String, Vec<(std::ops::Range<u64>, ())>> = HashMap::new();
-    let file = File::open(bed_file)?;
-    
-    // 首先收集所有区间
-    for line in io::BufReader::new(file).lines() {
-        let line = line?;
-        let fields: Vec<&str> = line.split('\t').collect();
-        if fields.len() >= 3 {
-            let chrom = fields[0].to_string();
-            let start: u64 = fields[1].parse()?;
-            let end: u64 = fields[2].parse()?;
-            interval_data.entry(chrom)
-                .or_default()
-                .push((start..end, ()));
-        }
-    }
-    
-    // 然后为每个染色体创建 IntervalTree
-    let intervals_map = interval_data.into_iter()
-        .map(|(chrom, intervals)| {
-            (chrom, IntervalTree::from_iter(intervals))
-        })
-        .collect();
-    
-    Ok(intervals_map)
-}
-
-fn process_records(
-    intervals_map: &HashMap<String, IntervalTree<u64, ()>>,
-    tid_to_name: HashMap<i32, String>,
-    receiver: Receiver<OrderedRecord>,
-    sender: Sender<OrderedRecord>,
-    processed_count: Arc<AtomicUsize>,
-) {
-    receiver.into_iter().par_bridge().for_each_with(sender, |s, mut ordered_record| {
-        let tid = ordered_record.record.tid();
-        if tid >= 0 {
-            if let Some(tid_name) = tid_to_name.get(&tid) {
-                if let Some(tree) = intervals_map.get(tid_name) {
-                    let record_start = ordered_record.record.pos() as u64;
-                    let record_end = ordered_record.record.cigar().end_pos() as u64;
-                    if tree.query(record_start..record_end).next().is_some() {
-                        if ordered_record.record.mapq() < 30 {
-                            ordered_record.record.set_mapq(60);
-                        }
-                    }
-                }
-            }
-        }
-        if let Err(e) = s.send(ordered_record) {
-            eprintln!("Error sending record: {}", e);
-        }
-        processed_count.fetch_add(1, Ordering::Relaxed);
-    });
-}
-
-fn update_bam_mapq(bam_file: &str, bed_file: &str, output_bam_file: &str) -> Result<(), Box<dyn Error>> {
-    println!("Reading BED file...");
-    let intervals_map = read_bed_file(bed_file)?;
-    println!("Found {} chromosomes in BED file", intervals_map.len());
-
-    println!("Opening BAM file...");
-    let mut bam = bam::Reader::from_path(bam_file)?;
-    let header = bam::Header::from_template(bam.header());
-    let bam_header = bam.header();
-    
-    println!("Creating chromosome name mapping...");
-    let mut tid_to_name = HashMap::new();
-    for tid in 0..bam_header.target_count() {
-        if let Ok(name) = std::str::from_utf8(bam_header.tid2name(tid)) {
-            tid_to_name.insert(tid as i32, name.to_string());
-        }
-    }
-    println!("Found {} chromosomes in BAM file", tid_to_name.len());
-    
-    println!("Creating output BAM file...");
-    let mut output_bam = bam::Writer::from_path(output_bam_file, &header, bam::Format::Bam)?;
-    output_bam.set_threads(4)?;
-
-    let (record_sender, record_receiver) = bounded(10000);
-    let (result_sender, result_receiver) = bounded(10000);
-    
-    let processed_count = Arc::new(AtomicUsize::new(0));
-    let processed_count_clone = processed_count.clone();
-
-    println!("Starting processing...");
-    crossbeam::scope(|scope| -> Result<(), Box<dyn Error>> {
-        // 处理记录的线程
-        scope.spawn(|_| {
-            process_records(
-                &intervals_map,
-                tid_to_name,
-                record_receiver,
-                result_sender,
-                processed_count_clone,
-            );
-        });
-
-        // 读取记录的线程
-        let read_handle = scope.spawn(|_| -> Result<usize, ProcessError> {
-            let mut count = 0;
-            let mut records = Vec::with_capacity(1000);
-            
-            for result in bam.records() {
-                match result {
-                    Ok(record) => {
-                        records.push(OrderedRecord {
-                            index: count,
-                            record,
-                        });
-                        count += 1;
-                        
-                        if records.len() >= 1000 {
-                            for record in records.drain(..) {
-                                if let Err(e) = record_sender.send(record) {
-                                    return Err(ProcessError::SendError(e.to_string()));
-                                }
-                            }
-                            if count % 1_000_000 == 0 {
-                                println!("Read {} records", count);
-                            }
-                        }
-                    }
-                    Err(e) => return Err(ProcessError::BamError(e)),
-                }
-            }
-            
-            // 发送剩余的记录
-            for record in records.drain(..) {
-                if let Err(e) = record_sender.send(record) {
-                    return Err(ProcessError::SendError(e.to_string()));
-                }
-            }
-            
-            drop(record_sender);
-            Ok(count)
-        });
-
-        // 写入记录的线程 - 使用排序的批量写入
-        let mut write_count = 0;
-        let mut next_index = 0;
-        let mut pending_records: Vec<OrderedRecord> = Vec::new();
-
-        for ordered_record in result_receiver {
-            if ordered_record.index == next_index {
-                // 如果是期望的下一条记录，直接写入
-                output_bam.write(&ordered_record.record)?;
-                write_count += 1;
-                next_index += 1;
-
-                // 检查是否有待处理的记录可以写入
-                pending_records.sort_by_key(|r: &OrderedRecord| r.index);
-                while let Some(pos) = pending_records.iter().position(|r| r.index == next_index) {
-                    let record = pending_records.remove(pos);
-                    output_bam.write(&record.record)?;
-                    write_count += 1;
-                    next_index += 1;
-                }
-            } else {
-                // 如果不是期望的下一条记录，加入待处理队列
-                pending_records.push(ordered_record);
-            }
-
-            if write_count % 1_000_000 == 0 {
-                println!("Wrote {} records", write_count);
-            }
-        }
-
-        // 处理剩余的记录
-        pending_records.sort_by_key(|r| r.index);
-        for record in pending_records {
-            output_bam.write(&record.record)?;
-            write_count += 1;
-        }
-
-        if let Ok(total_read) = read_handle.join() {
-            match total_read {
-                Ok(count) => {
-                    println!("Total records read: {}", count);
-                    println!("Total records processed: {}", processed_count.load(Ordering::Relaxed));
-                    println!("Total records written: {}", write_count);
-                }
-                Err(e) => eprintln!("Error in read thread: {}", e),
-            }
-        }
-
-        Ok(())
-    }).unwrap()?;
-
-    println!("Processing complete!");
-    Ok(())
-}
-
-fn main() -> Result<(), Box<dyn Error>> {
-    let matches = Command::new("BAM MAPQ Updater")
-        .version("1.0")
-        .author("Your Name <your.email@example.com>")
-        .about("Updates MAPQ values in a BAM file based on regions defined in a BED file")
-        .arg(Arg::new("bam")
-            .short('b')
-            .long("bam")
-            .value_name("BAM_FILE")
-            .help("Input BAM file")
-            .required(true))
-        .arg(Arg::new("bed")
-            .short('d')
-            .long("bed")
-            .value_name("BED_FILE")
-            .help("Input BED file")
-            .required(true))
-        .arg(Arg::new("output")
-            .short('o')
-            .long("output")
-            .value_name("OUTPUT_BAM_FILE")
-            .help("Output BAM file")
-            .required(true))
-        .get_matches();
-
-    let bam_file = matches.get_one::<String>("bam").unwrap();
-    let bed_file = matches.get_one::<String>("bed").unwrap();
-    let output_bam_file = matches.get_one::<String>("output").unwrap();
-
-    update_bam_mapq(bam_file, bed_file, output_bam_file)
-}
+use rust_htslib::bam::{self, Read, Record};
+use std::fs::File;
+use std::io::{self, BufRead};
+use intervaltree::IntervalTree;
+use std::collections::{HashMap, HashSet};
+use std::ops::Range;
+use rayon::prelude::*;
+use crossbeam::channel::{bounded, Receiver, RecvTimeoutError, Sender};
+use clap::{Arg, Command};
+use std::error::Error;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::cmp::Ordering as CmpOrdering;
+use std::time::Duration;
+
+mod region;
+use region::{merge_spans, ReadKey};
+
+mod coverage;
+use coverage::{CoverageAccumulator, CoverageConfig};
+
+mod policy;
+use policy::MapqPolicy;
+
+mod fastq;
+use fastq::{FastqConfig, FastqWriter};
+
+mod reorder;
+use reorder::{Indexed, ReorderBuffer};
+
+#[derive(Debug)]
+enum ProcessError {
+    IoError(io::Error),
+    BamError(rust_htslib::errors::Error),
+    SendError(String),
+}
+
+impl From<io::Error> for ProcessError {
+    fn from(err: io::Error) -> ProcessError {
+        ProcessError::IoError(err)
+    }
+}
+
+impl From<rust_htslib::errors::Error> for ProcessError {
+    fn from(err: rust_htslib::errors::Error) -> ProcessError {
+        ProcessError::BamError(err)
+    }
+}
+
+impl std::error::Error for ProcessError {}
+
+impl std::fmt::Display for ProcessError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProcessError::IoError(e) => write!(f, "IO error: {}", e),
+            ProcessError::BamError(e) => write!(f, "BAM error: {}", e),
+            ProcessError::SendError(e) => write!(f, "Send error: {}", e),
+        }
+    }
+}
+
+#[derive(Clone)]
+struct OrderedRecord {
+    index: usize,
+    record: Record,
+}
+
+// `OrderedRecord`s are ordered solely by `index` so a `ReorderBuffer` of
+// them acts as a min-heap keyed on arrival order.
+impl PartialEq for OrderedRecord {
+    fn eq(&self, other: &Self) -> bool {
+        self.index == other.index
+    }
+}
+
+impl Eq for OrderedRecord {}
+
+impl PartialOrd for OrderedRecord {
+    fn partial_cmp(&self, other: &Self) -> Option<CmpOrdering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for OrderedRecord {
+    fn cmp(&self, other: &Self) -> CmpOrdering {
+        self.index.cmp(&other.index)
+    }
+}
+
+impl Indexed for OrderedRecord {
+    fn index(&self) -> usize {
+        self.index
+    }
+}
+
+/// Read a BED file into per-chromosome `(start..end)` ranges, in file order.
+fn read_bed_intervals(bed_file: &str) -> Result<HashMap<String, Vec<Range<u64>>>, Box<dyn Error>> {
+    let mut interval_data: HashMap<String, Vec<Range<u64>>> = HashMap::new();
+    let file = File::open(bed_file)?;
+
+    for line in io::BufReader::new(file).lines() {
+        let line = line?;
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() >= 3 {
+            let chrom = fields[0].to_string();
+            let start: u64 = fields[1].parse()?;
+            let end: u64 = fields[2].parse()?;
+            interval_data.entry(chrom)
+                .or_default()
+                .push(start..end);
+        }
+    }
+
+    Ok(interval_data)
+}
+
+/// Build the interval trees used by `process_records` from raw BED ranges.
+fn build_interval_trees(
+    interval_data: &HashMap<String, Vec<Range<u64>>>,
+) -> HashMap<String, IntervalTree<u64, ()>> {
+    interval_data.iter()
+        .map(|(chrom, ranges)| {
+            let intervals = ranges.iter().map(|r| (r.clone(), ()));
+            (chrom.clone(), IntervalTree::from_iter(intervals))
+        })
+        .collect()
+}
+
+/// Build the tid -> chromosome name table shared by every reading mode.
+fn build_tid_to_name(header: &bam::HeaderView) -> HashMap<i32, String> {
+    let mut tid_to_name = HashMap::new();
+    for tid in 0..header.target_count() {
+        if let Ok(name) = std::str::from_utf8(header.tid2name(tid)) {
+            tid_to_name.insert(tid as i32, name.to_string());
+        }
+    }
+    tid_to_name
+}
+
+/// Build the chrom-name -> length table needed by the bigWig writer.
+fn build_chrom_sizes(header: &bam::HeaderView) -> HashMap<String, u32> {
+    let mut chrom_sizes = HashMap::new();
+    for tid in 0..header.target_count() {
+        if let Ok(name) = std::str::from_utf8(header.tid2name(tid)) {
+            if let Some(len) = header.target_len(tid) {
+                chrom_sizes.insert(name.to_string(), len as u32);
+            }
+        }
+    }
+    chrom_sizes
+}
+
+/// I/O settings shared by every reading mode: the output container format
+/// and the reference FASTA needed to read or write CRAM.
+struct IoConfig {
+    format: bam::Format,
+    reference: Option<String>,
+}
+
+/// Resolve the output container format from `--output-fmt`, falling back to
+/// the `-o` file extension, defaulting to BAM.
+fn resolve_output_format(explicit: Option<&str>, output_path: &str) -> Result<bam::Format, Box<dyn Error>> {
+    if let Some(fmt) = explicit {
+        return match fmt {
+            "bam" => Ok(bam::Format::Bam),
+            "cram" => Ok(bam::Format::Cram),
+            "sam" => Ok(bam::Format::Sam),
+            other => Err(format!("unknown --output-fmt '{}': expected bam, cram, or sam", other).into()),
+        };
+    }
+
+    match std::path::Path::new(output_path).extension().and_then(|e| e.to_str()) {
+        Some(ext) if ext.eq_ignore_ascii_case("cram") => Ok(bam::Format::Cram),
+        Some(ext) if ext.eq_ignore_ascii_case("sam") => Ok(bam::Format::Sam),
+        _ => Ok(bam::Format::Bam),
+    }
+}
+
+/// Reject a CRAM input file up front when no `--reference` was given,
+/// mirroring the equivalent check on the output side: htslib can't decode
+/// CRAM without a reference, and its own error for a missing one is far
+/// less clear than catching it here.
+fn check_cram_input_reference(bam_file: &str, reference: &Option<String>) -> Result<(), Box<dyn Error>> {
+    let is_cram = std::path::Path::new(bam_file).extension()
+        .and_then(|e| e.to_str())
+        .map(|ext| ext.eq_ignore_ascii_case("cram"))
+        .unwrap_or(false);
+    if is_cram && reference.is_none() {
+        return Err("CRAM input requires --reference REF.fa".into());
+    }
+    Ok(())
+}
+
+/// Write the accumulated coverage track(s) requested by `--coverage-out`.
+fn write_coverage_output(
+    coverage: &CoverageAccumulator,
+    coverage_config: &CoverageConfig,
+    tid_to_name: &HashMap<i32, String>,
+    #[cfg_attr(not(feature = "bigwig"), allow(unused_variables))]
+    chrom_sizes: &HashMap<String, u32>,
+) -> Result<(), Box<dyn Error>> {
+    let Some(out_path) = &coverage_config.out_path else {
+        return Ok(());
+    };
+
+    println!("Writing coverage bedGraph to {}...", out_path);
+    coverage.write_bedgraph(out_path, tid_to_name)?;
+
+    #[cfg(feature = "bigwig")]
+    {
+        let bigwig_path = format!("{}.bw", out_path.trim_end_matches(".bedgraph").trim_end_matches(".bg"));
+        println!("Writing coverage bigWig to {}...", bigwig_path);
+        coverage.write_bigwig(&bigwig_path, tid_to_name, chrom_sizes)?;
+    }
+
+    Ok(())
+}
+
+fn process_records(
+    intervals_map: &HashMap<String, IntervalTree<u64, ()>>,
+    tid_to_name: HashMap<i32, String>,
+    receiver: Receiver<OrderedRecord>,
+    sender: Sender<OrderedRecord>,
+    processed_count: Arc<AtomicUsize>,
+    coverage: Option<Arc<CoverageAccumulator>>,
+    mapq_policy: &MapqPolicy,
+    fastq: Option<Arc<FastqWriter>>,
+) {
+    receiver.into_iter().par_bridge().for_each_with(sender, |s, mut ordered_record| {
+        let tid = ordered_record.record.tid();
+        if tid >= 0 {
+            if let Some(tid_name) = tid_to_name.get(&tid) {
+                if let Some(tree) = intervals_map.get(tid_name) {
+                    let record_start = ordered_record.record.pos() as u64;
+                    let record_end = ordered_record.record.cigar().end_pos() as u64;
+
+                    // 区间树里的区间已经按染色体合并为互不重叠的片段，
+                    // 所以重叠长度可以直接累加，不会重复计数。
+                    let mut covered_bases: u64 = 0;
+                    for element in tree.query(record_start..record_end) {
+                        let overlap_start = element.range.start.max(record_start);
+                        let overlap_end = element.range.end.min(record_end);
+                        if overlap_end > overlap_start {
+                            covered_bases += overlap_end - overlap_start;
+                        }
+                    }
+
+                    if covered_bases > 0 {
+                        let read_len = record_end.saturating_sub(record_start).max(1);
+                        let overlap_frac = covered_bases as f64 / read_len as f64;
+
+                        if let Some(new_mapq) = mapq_policy.resolve(overlap_frac, ordered_record.record.mapq()) {
+                            ordered_record.record.set_mapq(new_mapq);
+                        }
+                        if let Some(coverage) = &coverage {
+                            coverage.add(tid, record_start, record_end);
+                        }
+                        if let Some(fastq) = &fastq {
+                            // Secondary/supplementary records are extra
+                            // alignments of a read already emitted under its
+                            // primary record; writing them too would give the
+                            // same qname multiple (possibly hard-clipped,
+                            // differently-stranded) FASTQ entries.
+                            let record = &ordered_record.record;
+                            if !record.is_secondary() && !record.is_supplementary() {
+                                if let Err(e) = fastq.write(record) {
+                                    eprintln!("Error writing FASTQ record: {}", e);
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        if let Err(e) = s.send(ordered_record) {
+            eprintln!("Error sending record: {}", e);
+        }
+        processed_count.fetch_add(1, Ordering::Relaxed);
+    });
+}
+
+/// Default number of out-of-order records the writer holds before it starts
+/// timing out on a stalled gap, see `--max-reorder`.
+const DEFAULT_MAX_REORDER: usize = 100_000;
+
+/// How long the writer waits for the next record, once the reorder heap is
+/// over capacity, before concluding the gap will never fill.
+const GAP_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Shared processing + writer pipeline: spawns the `process_records` worker
+/// and the reorder-on-write loop, and drives `produce` as the reader thread.
+/// `produce` is responsible for assigning monotonically increasing
+/// `OrderedRecord::index` values and sending them over `record_sender`.
+fn run_pipeline<'a>(
+    intervals_map: &'a HashMap<String, IntervalTree<u64, ()>>,
+    tid_to_name: HashMap<i32, String>,
+    header: &'a bam::Header,
+    output_bam_file: &str,
+    io_config: &IoConfig,
+    max_reorder: usize,
+    coverage: Option<Arc<CoverageAccumulator>>,
+    mapq_policy: &'a MapqPolicy,
+    fastq: Option<Arc<FastqWriter>>,
+    produce: impl FnOnce(Sender<OrderedRecord>) -> Result<usize, ProcessError> + Send + 'a,
+) -> Result<(), Box<dyn Error>> {
+    if io_config.format == bam::Format::Cram && io_config.reference.is_none() {
+        return Err("CRAM output requires --reference REF.fa".into());
+    }
+
+    println!("Creating output file ({:?})...", io_config.format);
+    let mut output_bam = bam::Writer::from_path(output_bam_file, header, io_config.format)?;
+    if let Some(reference) = &io_config.reference {
+        output_bam.set_reference(reference)?;
+    }
+    output_bam.set_threads(4)?;
+
+    let (record_sender, record_receiver) = bounded(10000);
+    let (result_sender, result_receiver) = bounded(10000);
+
+    let processed_count = Arc::new(AtomicUsize::new(0));
+    let processed_count_clone = processed_count.clone();
+
+    println!("Starting processing...");
+    crossbeam::scope(|scope| -> Result<(), Box<dyn Error>> {
+        // 处理记录的线程
+        scope.spawn(|_| {
+            process_records(
+                intervals_map,
+                tid_to_name,
+                record_receiver,
+                result_sender,
+                processed_count_clone,
+                coverage,
+                mapq_policy,
+                fastq,
+            );
+        });
+
+        // 读取记录的线程
+        let read_handle = scope.spawn(|_| produce(record_sender));
+
+        // 写入记录的线程 - 用最小堆做乱序重排，而不是每次都排序整个缓冲区
+        let mut write_count = 0;
+        let mut buffer: ReorderBuffer<OrderedRecord> = ReorderBuffer::new(max_reorder);
+
+        loop {
+            let ordered_record = match result_receiver.recv() {
+                Ok(r) => r,
+                Err(_) => break, // 通道已关闭，没有更多记录
+            };
+
+            for ready in buffer.accept(ordered_record) {
+                output_bam.write(&ready.record)?;
+                write_count += 1;
+            }
+
+            // 堆超过上限仍然缺口未补上，说明上游可能丢了一条记录：
+            // 此时改为带超时地接收，若 GAP_TIMEOUT 内没有任何记录到达就报错。
+            // 到达的记录仍然放进堆里，所以堆的大小不受 max_reorder 限制；
+            // 若记录持续到达而缺口始终未补上，要到流结束时才由 finish() 报错。
+            while buffer.is_over_capacity() {
+                match result_receiver.recv_timeout(GAP_TIMEOUT) {
+                    Ok(ordered_record) => {
+                        for ready in buffer.accept(ordered_record) {
+                            output_bam.write(&ready.record)?;
+                            write_count += 1;
+                        }
+                    }
+                    Err(RecvTimeoutError::Timeout) => {
+                        return Err(Box::new(ProcessError::SendError(format!(
+                            "reorder buffer stuck at {} entries waiting for record #{}; a record was likely dropped upstream",
+                            buffer.len(),
+                            buffer.next_index()
+                        ))));
+                    }
+                    Err(RecvTimeoutError::Disconnected) => break,
+                }
+            }
+
+            if write_count % 1_000_000 == 0 {
+                println!("Wrote {} records", write_count);
+            }
+        }
+
+        // 流结束后，堆里剩下的记录必须正好是连续的 next_index.. ，
+        // 否则说明中间漏掉了一条记录。
+        match buffer.finish() {
+            Ok(remaining) => {
+                for record in remaining {
+                    output_bam.write(&record.record)?;
+                    write_count += 1;
+                }
+            }
+            Err((expected, found)) => {
+                return Err(Box::new(ProcessError::SendError(format!(
+                    "gap in output stream: expected record #{} but next available is #{}; a record was dropped",
+                    expected, found
+                ))));
+            }
+        }
+
+        if let Ok(total_read) = read_handle.join() {
+            match total_read {
+                Ok(count) => {
+                    println!("Total records read: {}", count);
+                    println!("Total records processed: {}", processed_count.load(Ordering::Relaxed));
+                    println!("Total records written: {}", write_count);
+                }
+                Err(e) => eprintln!("Error in read thread: {}", e),
+            }
+        }
+
+        Ok(())
+    }).unwrap()?;
+
+    println!("Processing complete!");
+    Ok(())
+}
+
+/// Linear-scan mode: stream every record in the BAM through `process_records`.
+fn update_bam_mapq_scan(
+    bam_file: &str,
+    output_bam_file: &str,
+    intervals_map: &HashMap<String, IntervalTree<u64, ()>>,
+    io_config: &IoConfig,
+    max_reorder: usize,
+    coverage_config: &CoverageConfig,
+    mapq_policy: &MapqPolicy,
+    fastq_config: &FastqConfig,
+) -> Result<(), Box<dyn Error>> {
+    check_cram_input_reference(bam_file, &io_config.reference)?;
+
+    println!("Opening input file...");
+    let mut bam = bam::Reader::from_path(bam_file)?;
+    if let Some(reference) = &io_config.reference {
+        bam.set_reference(reference)?;
+    }
+    let header = bam::Header::from_template(bam.header());
+
+    println!("Creating chromosome name mapping...");
+    let tid_to_name = build_tid_to_name(bam.header());
+    println!("Found {} chromosomes in BAM file", tid_to_name.len());
+    let tid_to_name_for_coverage = tid_to_name.clone();
+    let chrom_sizes = build_chrom_sizes(bam.header());
+
+    let coverage = coverage_config.out_path.as_ref()
+        .map(|_| Arc::new(CoverageAccumulator::new(coverage_config.bin_size)));
+    let coverage_clone = coverage.clone();
+
+    let fastq = match &fastq_config.out_prefix {
+        Some(prefix) => Some(Arc::new(FastqWriter::create(prefix)?)),
+        None => None,
+    };
+    let fastq_clone = fastq.clone();
+
+    run_pipeline(intervals_map, tid_to_name, &header, output_bam_file, io_config, max_reorder, coverage_clone, mapq_policy, fastq_clone, move |record_sender| {
+        let mut count = 0;
+        let mut records = Vec::with_capacity(1000);
+
+        for result in bam.records() {
+            match result {
+                Ok(record) => {
+                    records.push(OrderedRecord {
+                        index: count,
+                        record,
+                    });
+                    count += 1;
+
+                    if records.len() >= 1000 {
+                        for record in records.drain(..) {
+                            if let Err(e) = record_sender.send(record) {
+                                return Err(ProcessError::SendError(e.to_string()));
+                            }
+                        }
+                        if count % 1_000_000 == 0 {
+                            println!("Read {} records", count);
+                        }
+                    }
+                }
+                Err(e) => return Err(ProcessError::BamError(e)),
+            }
+        }
+
+        // 发送剩余的记录
+        for record in records.drain(..) {
+            if let Err(e) = record_sender.send(record) {
+                return Err(ProcessError::SendError(e.to_string()));
+            }
+        }
+
+        drop(record_sender);
+        Ok(count)
+    })?;
+
+    if let Some(coverage) = &coverage {
+        write_coverage_output(coverage, coverage_config, &tid_to_name_for_coverage, &chrom_sizes)?;
+    }
+    if let Some(fastq) = fastq {
+        if let Ok(fastq) = Arc::try_unwrap(fastq) {
+            fastq.finish()?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Indexed mode: merge the BED intervals per chromosome into non-overlapping
+/// spans and `fetch` each one instead of scanning the whole file. A read that
+/// bridges the gap between two merged spans is returned by both fetches, so
+/// fetched reads are deduped by `ReadKey`. With
+/// `regions_only` the output contains just the fetched reads; otherwise a
+/// second linear scan appends whatever wasn't fetched, so the output still
+/// contains every read in the input.
+fn update_bam_mapq_indexed(
+    bam_file: &str,
+    output_bam_file: &str,
+    raw_intervals: &HashMap<String, Vec<Range<u64>>>,
+    intervals_map: &HashMap<String, IntervalTree<u64, ()>>,
+    regions_only: bool,
+    io_config: &IoConfig,
+    max_reorder: usize,
+    coverage_config: &CoverageConfig,
+    mapq_policy: &MapqPolicy,
+    fastq_config: &FastqConfig,
+) -> Result<(), Box<dyn Error>> {
+    check_cram_input_reference(bam_file, &io_config.reference)?;
+
+    println!("Opening indexed input file...");
+    let mut idx_reader = bam::IndexedReader::from_path(bam_file)?;
+    if let Some(reference) = &io_config.reference {
+        idx_reader.set_reference(reference)?;
+    }
+    let header = bam::Header::from_template(idx_reader.header());
+
+    println!("Creating chromosome name mapping...");
+    let tid_to_name = build_tid_to_name(idx_reader.header());
+    println!("Found {} chromosomes in BAM file", tid_to_name.len());
+    let tid_to_name_for_coverage = tid_to_name.clone();
+    let chrom_sizes = build_chrom_sizes(idx_reader.header());
+    let name_to_tid: HashMap<String, i32> = tid_to_name.iter()
+        .map(|(tid, name)| (name.clone(), *tid))
+        .collect();
+
+    println!("Merging target regions into non-overlapping spans...");
+    let merged_spans = merge_spans(raw_intervals);
+    let span_count: usize = merged_spans.values().map(|spans| spans.len()).sum();
+    println!("Merged into {} spans across {} chromosomes", span_count, merged_spans.len());
+
+    let plain_bam_file = bam_file.to_string();
+
+    let coverage = coverage_config.out_path.as_ref()
+        .map(|_| Arc::new(CoverageAccumulator::new(coverage_config.bin_size)));
+    let coverage_clone = coverage.clone();
+
+    let fastq = match &fastq_config.out_prefix {
+        Some(prefix) => Some(Arc::new(FastqWriter::create(prefix)?)),
+        None => None,
+    };
+    let fastq_clone = fastq.clone();
+
+    let reference = io_config.reference.clone();
+
+    run_pipeline(intervals_map, tid_to_name, &header, output_bam_file, io_config, max_reorder, coverage_clone, mapq_policy, fastq_clone, move |record_sender| {
+        let mut count = 0usize;
+        let mut emitted: HashSet<ReadKey> = HashSet::new();
+
+        // merged_spans is a HashMap, so its iteration order is arbitrary;
+        // visit chromosomes in header tid order so the fetched reads get
+        // OrderedRecord::index in coordinate order. With --regions-only the
+        // output is then coordinate-sorted like the scan-mode output; in
+        // pass-through mode the complement scan below is appended after the
+        // fetched reads, so the whole output is not coordinate-sorted.
+        let mut ordered_chroms: Vec<(&String, i32)> = merged_spans.keys()
+            .filter_map(|chrom| name_to_tid.get(chrom.as_str()).map(|tid| (chrom, *tid)))
+            .collect();
+        ordered_chroms.sort_by_key(|(_, tid)| *tid);
+
+        for (chrom, tid) in ordered_chroms {
+            let spans = &merged_spans[chrom];
+            for span in spans {
+                idx_reader.fetch((tid, span.start as i64, span.end as i64))
+                    .map_err(ProcessError::BamError)?;
+                for result in idx_reader.records() {
+                    let record = result.map_err(ProcessError::BamError)?;
+                    let key = ReadKey {
+                        qname: record.qname().to_vec(),
+                        flags: record.flags(),
+                        tid: record.tid(),
+                        pos: record.pos(),
+                    };
+                    if !emitted.insert(key) {
+                        continue; // 已经在相邻 span 中发出过
+                    }
+                    let index = count;
+                    count += 1;
+                    if let Err(e) = record_sender.send(OrderedRecord { index, record }) {
+                        return Err(ProcessError::SendError(e.to_string()));
+                    }
+                }
+            }
+        }
+        println!("Fetched {} in-region reads from {} spans", count, span_count);
+
+        if !regions_only {
+            println!("Pass-through mode: scanning whole file for untouched reads...");
+            let mut full_reader = bam::Reader::from_path(&plain_bam_file)?;
+            if let Some(reference) = &reference {
+                full_reader.set_reference(reference)?;
+            }
+            for result in full_reader.records() {
+                let record = result.map_err(ProcessError::BamError)?;
+                let key = ReadKey {
+                    qname: record.qname().to_vec(),
+                    flags: record.flags(),
+                    tid: record.tid(),
+                    pos: record.pos(),
+                };
+                if emitted.contains(&key) {
+                    continue;
+                }
+                let index = count;
+                count += 1;
+                if let Err(e) = record_sender.send(OrderedRecord { index, record }) {
+                    return Err(ProcessError::SendError(e.to_string()));
+                }
+            }
+        }
+
+        drop(record_sender);
+        Ok(count)
+    })?;
+
+    if let Some(coverage) = &coverage {
+        write_coverage_output(coverage, coverage_config, &tid_to_name_for_coverage, &chrom_sizes)?;
+    }
+    if let Some(fastq) = fastq {
+        if let Ok(fastq) = Arc::try_unwrap(fastq) {
+            fastq.finish()?;
+        }
+    }
+
+    Ok(())
+}
+
+fn update_bam_mapq(
+    bam_file: &str,
+    bed_file: &str,
+    output_bam_file: &str,
+    indexed: bool,
+    regions_only: bool,
+    io_config: &IoConfig,
+    max_reorder: usize,
+    coverage_config: &CoverageConfig,
+    mapq_policy: &MapqPolicy,
+    fastq_config: &FastqConfig,
+) -> Result<(), Box<dyn Error>> {
+    println!("Reading BED file...");
+    let raw_intervals = read_bed_intervals(bed_file)?;
+    // 合并后的区间用于构建区间树：既避免了重叠区域重复计入 overlap_frac，
+    // 也是索引模式下 fetch span 的同一份数据来源。
+    let merged_intervals = merge_spans(&raw_intervals);
+    let intervals_map = build_interval_trees(&merged_intervals);
+    println!("Found {} chromosomes in BED file", intervals_map.len());
+
+    if indexed {
+        update_bam_mapq_indexed(bam_file, output_bam_file, &raw_intervals, &intervals_map, regions_only, io_config, max_reorder, coverage_config, mapq_policy, fastq_config)
+    } else {
+        if regions_only {
+            eprintln!("Warning: --regions-only has no effect without --indexed; ignoring");
+        }
+        update_bam_mapq_scan(bam_file, output_bam_file, &intervals_map, io_config, max_reorder, coverage_config, mapq_policy, fastq_config)
+    }
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let matches = Command::new("BAM MAPQ Updater")
+        .version("1.0")
+        .author("Your Name <your.email@example.com>")
+        .about("Updates MAPQ values in a BAM file based on regions defined in a BED file")
+        .arg(Arg::new("bam")
+            .short('b')
+            .long("bam")
+            .value_name("BAM_FILE")
+            .help("Input BAM file")
+            .required(true))
+        .arg(Arg::new("bed")
+            .short('d')
+            .long("bed")
+            .value_name("BED_FILE")
+            .help("Input BED file")
+            .required(true))
+        .arg(Arg::new("output")
+            .short('o')
+            .long("output")
+            .value_name("OUTPUT_BAM_FILE")
+            .help("Output file (BAM/CRAM/SAM, format inferred from extension unless --output-fmt is given)")
+            .required(true))
+        .arg(Arg::new("output-fmt")
+            .long("output-fmt")
+            .value_name("FORMAT")
+            .help("Output format: bam, cram, or sam (overrides the -o extension)"))
+        .arg(Arg::new("reference")
+            .long("reference")
+            .value_name("REF.fa")
+            .help("Reference FASTA, required for reading or writing CRAM"))
+        .arg(Arg::new("indexed")
+            .long("indexed")
+            .help("Use the BAM/CRAM index to fetch only the target regions instead of scanning the whole file")
+            .action(clap::ArgAction::SetTrue))
+        .arg(Arg::new("regions-only")
+            .long("regions-only")
+            .help("With --indexed, write only the in-region reads instead of passing through the rest of the file")
+            .action(clap::ArgAction::SetTrue))
+        .arg(Arg::new("max-reorder")
+            .long("max-reorder")
+            .value_name("N")
+            .help("Cap on out-of-order records held by the writer before it blocks waiting for a gap to fill")
+            .value_parser(clap::value_parser!(usize))
+            .default_value(DEFAULT_MAX_REORDER.to_string()))
+        .arg(Arg::new("coverage-out")
+            .long("coverage-out")
+            .value_name("FILE")
+            .help("Write a bedGraph (and, with the bigwig feature, a bigWig) of depth over target-overlapping reads"))
+        .arg(Arg::new("bin-size")
+            .long("bin-size")
+            .value_name("BP")
+            .help("Bin size in bases for --coverage-out")
+            .value_parser(clap::value_parser!(u64).range(1..))
+            .default_value("100"))
+        .arg(Arg::new("min-overlap-frac")
+            .long("min-overlap-frac")
+            .value_name("FRACTION")
+            .help("Simple policy: minimum fraction of the read that must fall inside target regions to qualify for remap")
+            .value_parser(clap::value_parser!(f64))
+            .default_value("0.0"))
+        .arg(Arg::new("mapq-floor")
+            .long("mapq-floor")
+            .value_name("MAPQ")
+            .help("Simple policy: only remap reads whose current MAPQ is below this value")
+            .value_parser(clap::value_parser!(u8))
+            .default_value("30"))
+        .arg(Arg::new("set-mapq")
+            .long("set-mapq")
+            .value_name("MAPQ")
+            .help("Simple policy: MAPQ to assign when a read qualifies")
+            .value_parser(clap::value_parser!(u8))
+            .default_value("60"))
+        .arg(Arg::new("policy-file")
+            .long("policy-file")
+            .value_name("FILE")
+            .help("Full rule table (TOML or JSON) evaluated in order, used instead of --min-overlap-frac/--mapq-floor/--set-mapq (cannot be combined with them)")
+            .conflicts_with_all(["min-overlap-frac", "mapq-floor", "set-mapq"]))
+        .arg(Arg::new("fastq-out")
+            .long("fastq-out")
+            .value_name("PREFIX")
+            .help("Write target-overlapping reads as gzipped FASTQ to PREFIX_1.fq.gz/PREFIX_2.fq.gz"))
+        .get_matches();
+
+    let bam_file = matches.get_one::<String>("bam").unwrap();
+    let bed_file = matches.get_one::<String>("bed").unwrap();
+    let output_bam_file = matches.get_one::<String>("output").unwrap();
+    let indexed = matches.get_flag("indexed");
+    let regions_only = matches.get_flag("regions-only");
+    let max_reorder = *matches.get_one::<usize>("max-reorder").unwrap();
+    let coverage_config = CoverageConfig {
+        out_path: matches.get_one::<String>("coverage-out").cloned(),
+        bin_size: *matches.get_one::<u64>("bin-size").unwrap(),
+    };
+    let io_config = IoConfig {
+        format: resolve_output_format(matches.get_one::<String>("output-fmt").map(String::as_str), output_bam_file)?,
+        reference: matches.get_one::<String>("reference").cloned(),
+    };
+    let mapq_policy = if let Some(policy_file) = matches.get_one::<String>("policy-file") {
+        MapqPolicy::from_file(policy_file)?
+    } else {
+        MapqPolicy::simple(
+            *matches.get_one::<f64>("min-overlap-frac").unwrap(),
+            *matches.get_one::<u8>("mapq-floor").unwrap(),
+            *matches.get_one::<u8>("set-mapq").unwrap(),
+        )
+    };
+    let fastq_config = FastqConfig {
+        out_prefix: matches.get_one::<String>("fastq-out").cloned(),
+    };
+
+    update_bam_mapq(bam_file, bed_file, output_bam_file, indexed, regions_only, &io_config, max_reorder, &coverage_config, &mapq_policy, &fastq_config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolve_output_format_prefers_explicit_over_extension() {
+        let fmt = resolve_output_format(Some("cram"), "out.bam").unwrap();
+        assert_eq!(fmt, bam::Format::Cram);
+    }
+
+    #[test]
+    fn resolve_output_format_rejects_unknown_explicit_value() {
+        assert!(resolve_output_format(Some("vcf"), "out.bam").is_err());
+    }
+
+    #[test]
+    fn resolve_output_format_falls_back_to_extension() {
+        assert_eq!(resolve_output_format(None, "out.cram").unwrap(), bam::Format::Cram);
+        assert_eq!(resolve_output_format(None, "out.sam").unwrap(), bam::Format::Sam);
+        assert_eq!(resolve_output_format(None, "out.bam").unwrap(), bam::Format::Bam);
+        assert_eq!(resolve_output_format(None, "out").unwrap(), bam::Format::Bam);
+    }
+
+    #[test]
+    fn check_cram_input_reference_requires_reference_for_cram_extension() {
+        assert!(check_cram_input_reference("in.cram", &None).is_err());
+        assert!(check_cram_input_reference("in.cram", &Some("ref.fa".to_string())).is_ok());
+    }
+
+    #[test]
+    fn check_cram_input_reference_ignores_non_cram_input() {
+        assert!(check_cram_input_reference("in.bam", &None).is_ok());
+    }
+}
@@ -0,0 +1,94 @@
+use serde::Deserialize;
+use std::error::Error;
+use std::path::Path;
+
+/// One entry of the MAPQ remap rule table: if a read's overlap fraction with
+/// target regions is at least `min_frac` and its current MAPQ is below
+/// `in_mapq_below`, its MAPQ is set to `set_mapq`.
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct MapqRule {
+    pub min_frac: f64,
+    pub in_mapq_below: u8,
+    pub set_mapq: u8,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct PolicyFile {
+    rules: Vec<MapqRule>,
+}
+
+/// An ordered rule table driving the MAPQ remap, evaluated top to bottom.
+#[derive(Debug, Clone)]
+pub struct MapqPolicy {
+    rules: Vec<MapqRule>,
+}
+
+impl MapqPolicy {
+    /// The `--min-overlap-frac` / `--mapq-floor` / `--set-mapq` simple path:
+    /// a single rule standing in for the full `--policy-file` table.
+    pub fn simple(min_overlap_frac: f64, mapq_floor: u8, set_mapq: u8) -> MapqPolicy {
+        MapqPolicy {
+            rules: vec![MapqRule {
+                min_frac: min_overlap_frac,
+                in_mapq_below: mapq_floor,
+                set_mapq,
+            }],
+        }
+    }
+
+    /// Load the full rule table from a `--policy-file`, in TOML or JSON,
+    /// picked by extension.
+    pub fn from_file(path: &str) -> Result<MapqPolicy, Box<dyn Error>> {
+        let text = std::fs::read_to_string(path)?;
+        let parsed: PolicyFile = match Path::new(path).extension().and_then(|e| e.to_str()) {
+            Some(ext) if ext.eq_ignore_ascii_case("json") => serde_json::from_str(&text)?,
+            Some(ext) if ext.eq_ignore_ascii_case("toml") => toml::from_str(&text)?,
+            Some(ext) => return Err(format!(
+                "unsupported policy file extension '{}': expected .json or .toml", ext
+            ).into()),
+            None => return Err("policy file has no extension; expected .json or .toml".into()),
+        };
+
+        if parsed.rules.is_empty() {
+            return Err("policy file defines no rules".into());
+        }
+        Ok(MapqPolicy { rules: parsed.rules })
+    }
+
+    /// Evaluate the rules in order; the first one whose thresholds are met
+    /// wins. Returns `None` if no rule applies, leaving MAPQ untouched.
+    pub fn resolve(&self, overlap_frac: f64, mapq: u8) -> Option<u8> {
+        self.rules.iter()
+            .find(|rule| overlap_frac >= rule.min_frac && mapq < rule.in_mapq_below)
+            .map(|rule| rule.set_mapq)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn simple_policy_remaps_below_floor_and_above_overlap() {
+        let policy = MapqPolicy::simple(0.5, 30, 60);
+
+        assert_eq!(policy.resolve(0.9, 10), Some(60));
+        assert_eq!(policy.resolve(0.4, 10), None, "below overlap threshold");
+        assert_eq!(policy.resolve(0.9, 30), None, "mapq already at/above floor");
+    }
+
+    #[test]
+    fn resolve_takes_the_first_matching_rule_in_order() {
+        let policy = MapqPolicy {
+            rules: vec![
+                MapqRule { min_frac: 0.8, in_mapq_below: 30, set_mapq: 60 },
+                MapqRule { min_frac: 0.2, in_mapq_below: 30, set_mapq: 40 },
+            ],
+        };
+
+        // Satisfies both rules; the first one in the table wins.
+        assert_eq!(policy.resolve(0.9, 10), Some(60));
+        // Only satisfies the second rule.
+        assert_eq!(policy.resolve(0.5, 10), Some(40));
+    }
+}
@@ -0,0 +1,125 @@
+use std::collections::HashMap;
+use std::ops::Range;
+
+/// Coalesce the per-chromosome BED intervals into sorted, non-overlapping
+/// spans so each one can be handed to `IndexedReader::fetch` exactly once.
+///
+/// Adjacent or overlapping ranges are merged, so no two spans overlap. A read
+/// longer than the gap between two spans is still returned by both fetches;
+/// callers dedupe those with `ReadKey`.
+pub fn merge_spans(raw: &HashMap<String, Vec<Range<u64>>>) -> HashMap<String, Vec<Range<u64>>> {
+    raw.iter()
+        .map(|(chrom, ranges)| {
+            let mut sorted = ranges.clone();
+            sorted.sort_by_key(|r| r.start);
+
+            let mut merged: Vec<Range<u64>> = Vec::with_capacity(sorted.len());
+            for r in sorted {
+                match merged.last_mut() {
+                    Some(last) if r.start <= last.end => {
+                        if r.end > last.end {
+                            last.end = r.end;
+                        }
+                    }
+                    _ => merged.push(r),
+                }
+            }
+            (chrom.clone(), merged)
+        })
+        .collect()
+}
+
+/// Identity used to dedupe a read that may be yielded by two adjacent
+/// `fetch` spans (e.g. a read spanning the boundary between them).
+///
+/// This must identify the *record*, not just its alignment coordinates:
+/// `(tid, pos, flags, mate_pos)` is exactly the signature PCR/optical
+/// duplicate pairs share, and BAMs with duplicate-flagged reads in a target
+/// region are common. Keying on the qname (which is unique per template)
+/// plus `flags` (to tell apart mate 1/2 and secondary/supplementary records
+/// sharing that qname) plus `tid`/`pos` (to tell apart multiple secondary/
+/// supplementary alignments of the same mate, possibly on different
+/// chromosomes) only ever collapses the genuinely-same record re-fetched
+/// across a span boundary.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ReadKey {
+    pub qname: Vec<u8>,
+    pub flags: u16,
+    pub tid: i32,
+    pub pos: i64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ranges(pairs: &[(u64, u64)]) -> Vec<Range<u64>> {
+        pairs.iter().map(|&(s, e)| s..e).collect()
+    }
+
+    #[test]
+    fn merge_spans_joins_overlapping_and_adjacent_ranges() {
+        let mut raw = HashMap::new();
+        raw.insert("chr1".to_string(), ranges(&[(10, 20), (15, 25), (25, 30), (100, 110)]));
+
+        let merged = merge_spans(&raw);
+
+        assert_eq!(merged["chr1"], ranges(&[(10, 30), (100, 110)]));
+    }
+
+    #[test]
+    fn merge_spans_keeps_disjoint_ranges_and_sorts_them() {
+        let mut raw = HashMap::new();
+        raw.insert("chr1".to_string(), ranges(&[(100, 110), (0, 5)]));
+
+        let merged = merge_spans(&raw);
+
+        assert_eq!(merged["chr1"], ranges(&[(0, 5), (100, 110)]));
+    }
+
+    #[test]
+    fn merge_spans_handles_each_chromosome_independently() {
+        let mut raw = HashMap::new();
+        raw.insert("chr1".to_string(), ranges(&[(0, 10)]));
+        raw.insert("chr2".to_string(), ranges(&[(0, 10), (5, 15)]));
+
+        let merged = merge_spans(&raw);
+
+        assert_eq!(merged["chr1"], ranges(&[(0, 10)]));
+        assert_eq!(merged["chr2"], ranges(&[(0, 15)]));
+    }
+
+    #[test]
+    fn read_key_distinguishes_duplicate_pairs_sharing_position() {
+        // Two genuinely distinct templates (e.g. PCR/optical duplicates)
+        // commonly share (tid, pos, flags) but never qname.
+        let a = ReadKey { qname: b"read-a".to_vec(), flags: 99, tid: 0, pos: 1000 };
+        let b = ReadKey { qname: b"read-b".to_vec(), flags: 99, tid: 0, pos: 1000 };
+        assert_ne!(a, b);
+    }
+
+    #[test]
+    fn read_key_collapses_the_same_record_seen_twice() {
+        // A read re-fetched across two adjacent span boundaries yields
+        // identical qname/flags/tid/pos both times.
+        let a = ReadKey { qname: b"read-a".to_vec(), flags: 99, tid: 0, pos: 1000 };
+        let b = ReadKey { qname: b"read-a".to_vec(), flags: 99, tid: 0, pos: 1000 };
+        assert_eq!(a, b);
+    }
+
+    #[test]
+    fn read_key_distinguishes_mates_of_the_same_template() {
+        let mate1 = ReadKey { qname: b"read-a".to_vec(), flags: 99, tid: 0, pos: 1000 };
+        let mate2 = ReadKey { qname: b"read-a".to_vec(), flags: 147, tid: 1, pos: 1200 };
+        assert_ne!(mate1, mate2);
+    }
+
+    #[test]
+    fn read_key_distinguishes_same_qname_and_flags_on_different_chromosomes() {
+        // Two secondary/supplementary alignments of the same mate can share
+        // flags and local offset while mapping to different chromosomes.
+        let a = ReadKey { qname: b"read-a".to_vec(), flags: 2304, tid: 0, pos: 500 };
+        let b = ReadKey { qname: b"read-a".to_vec(), flags: 2304, tid: 1, pos: 500 };
+        assert_ne!(a, b);
+    }
+}
@@ -0,0 +1,158 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+
+/// Anything that occupies a slot in a monotonically increasing, zero-based
+/// sequence -- the position `run_pipeline`'s writer needs it written at.
+pub trait Indexed {
+    fn index(&self) -> usize;
+}
+
+/// Reassembles a stream of `T`s that can arrive out of order (by
+/// `index()`) back into sequence, using a min-heap instead of re-sorting
+/// the whole buffer on every arrival. `max_reorder` is only a threshold
+/// reported by `is_over_capacity`; `accept` never refuses an item, so the
+/// heap itself has no size limit.
+pub struct ReorderBuffer<T: Ord> {
+    next_index: usize,
+    max_reorder: usize,
+    heap: BinaryHeap<Reverse<T>>,
+}
+
+impl<T: Ord + Indexed> ReorderBuffer<T> {
+    pub fn new(max_reorder: usize) -> Self {
+        ReorderBuffer {
+            next_index: 0,
+            max_reorder,
+            heap: BinaryHeap::new(),
+        }
+    }
+
+    /// Accept an arriving item and return everything now ready to emit, in
+    /// order. Usually empty or one item; more than one if `item` filled a
+    /// gap that let a run of already-buffered items through too.
+    pub fn accept(&mut self, item: T) -> Vec<T> {
+        self.heap.push(Reverse(item));
+        self.drain_ready()
+    }
+
+    fn drain_ready(&mut self) -> Vec<T> {
+        let mut ready = Vec::new();
+        while let Some(Reverse(top)) = self.heap.peek() {
+            if top.index() != self.next_index {
+                break;
+            }
+            let Reverse(item) = self.heap.pop().unwrap();
+            self.next_index += 1;
+            ready.push(item);
+        }
+        ready
+    }
+
+    /// True once the heap has grown past `max_reorder` without the gap at
+    /// `next_index()` filling -- the caller should apply backpressure.
+    pub fn is_over_capacity(&self) -> bool {
+        self.heap.len() > self.max_reorder
+    }
+
+    pub fn len(&self) -> usize {
+        self.heap.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.heap.is_empty()
+    }
+
+    pub fn next_index(&self) -> usize {
+        self.next_index
+    }
+
+    /// Consume the buffer at end of stream. Every remaining item must form
+    /// the contiguous run starting at `next_index()`; anything else means a
+    /// record was dropped upstream, reported as `(expected, found)`.
+    pub fn finish(mut self) -> Result<Vec<T>, (usize, usize)> {
+        let mut remaining = Vec::with_capacity(self.heap.len());
+        while let Some(Reverse(item)) = self.heap.pop() {
+            if item.index() != self.next_index {
+                return Err((self.next_index, item.index()));
+            }
+            self.next_index += 1;
+            remaining.push(item);
+        }
+        Ok(remaining)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq, Eq)]
+    struct Item(usize);
+
+    impl Indexed for Item {
+        fn index(&self) -> usize {
+            self.0
+        }
+    }
+
+    impl PartialOrd for Item {
+        fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+            Some(self.cmp(other))
+        }
+    }
+
+    impl Ord for Item {
+        fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+            self.0.cmp(&other.0)
+        }
+    }
+
+    fn indices(items: &[Item]) -> Vec<usize> {
+        items.iter().map(|i| i.0).collect()
+    }
+
+    #[test]
+    fn emits_immediately_when_already_in_order() {
+        let mut buf = ReorderBuffer::new(10);
+        assert_eq!(indices(&buf.accept(Item(0))), vec![0]);
+        assert_eq!(indices(&buf.accept(Item(1))), vec![1]);
+    }
+
+    #[test]
+    fn buffers_out_of_order_items_until_the_gap_fills() {
+        let mut buf = ReorderBuffer::new(10);
+        assert_eq!(indices(&buf.accept(Item(2))), Vec::<usize>::new());
+        assert_eq!(indices(&buf.accept(Item(1))), Vec::<usize>::new());
+        // The missing 0 arrives, releasing 0, 1, 2 in order in one go.
+        assert_eq!(indices(&buf.accept(Item(0))), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn is_over_capacity_once_the_gap_outgrows_max_reorder() {
+        let mut buf = ReorderBuffer::new(2);
+        buf.accept(Item(1));
+        buf.accept(Item(2));
+        assert!(!buf.is_over_capacity());
+        buf.accept(Item(3));
+        assert!(buf.is_over_capacity());
+    }
+
+    #[test]
+    fn finish_drains_a_contiguous_trailing_run() {
+        let mut buf = ReorderBuffer::new(10);
+        // Item 0 has already been written. `accept` would emit 1 and 2 at
+        // once, so seed the heap directly to exercise `finish` on its own.
+        buf.next_index = 1;
+        buf.heap.push(Reverse(Item(2)));
+        buf.heap.push(Reverse(Item(1)));
+        assert_eq!(indices(&buf.finish().unwrap()), vec![1, 2]);
+    }
+
+    #[test]
+    fn finish_reports_a_gap_left_by_a_dropped_record() {
+        let mut buf = ReorderBuffer::new(10);
+        buf.accept(Item(3));
+        // index 0 never arrived; the contiguous run can't start.
+        assert_eq!(buf.finish().unwrap_err(), (0, 3));
+    }
+}